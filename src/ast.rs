@@ -4,6 +4,9 @@ use anyhow::Result;
 use pest::{iterators::Pair, Parser};
 use pest_derive::Parser;
 
+use crate::diagnostics::Diagnostic;
+use crate::span::Spanned;
+
 #[derive(Parser, Debug, Clone)]
 #[grammar = "./grammar/mml.pest"]
 pub struct MmlParser;
@@ -87,21 +90,38 @@ pub enum CommentKind {
 }
 
 impl MmlAst {
-    pub fn parse(input: &str) -> Result<Vec<MmlAst>> {
+    /// Parse `input` into its command nodes, aggregating every command
+    /// that fails to convert instead of bailing out on the first one. A
+    /// malformed command no longer vanishes silently: it is reported as a
+    /// [`Diagnostic`] alongside the `Vec<Spanned<MmlAst>>` of everything
+    /// that did parse, each node tagged with the byte range it came from.
+    /// Input the grammar itself rejects is not collected this way: the
+    /// whole call returns `Err` with that single pest error.
+    pub fn parse(input: &str) -> Result<(Vec<Spanned<MmlAst>>, Vec<Diagnostic>)> {
         let parsed = MmlParser::parse(Rule::mml, input)?;
-        let mut ast: Vec<MmlAst> = Vec::new();
+        let mut ast: Vec<Spanned<MmlAst>> = Vec::new();
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
         for pair in parsed {
             for inner_pair in pair.into_inner() {
-                let node = MmlAst::parse_command(inner_pair)?;
-                if let Some(node) = node {
-                    ast.push(node);
+                let span_pair = inner_pair.clone();
+                let span = inner_pair.as_span();
+                let range = span.start()..span.end();
+                match MmlAst::parse_command(inner_pair) {
+                    Ok(node) => ast.push(Spanned::new(node, range)),
+                    Err(e) => diagnostics.push(Diagnostic::from_pair(&span_pair, e.to_string())),
                 }
             }
         }
-        Ok(ast)
+        Ok((ast, diagnostics))
+    }
+
+    /// Find the command whose span contains `offset`, e.g. to map an
+    /// editor cursor position back to the command under it.
+    pub fn node_at(ast: &[Spanned<MmlAst>], offset: usize) -> Option<&Spanned<MmlAst>> {
+        ast.iter().find(|node| node.contains(offset))
     }
 
-    fn parse_command(pair: Pair<Rule>) -> Result<Option<MmlAst>> {
+    fn parse_command(pair: Pair<Rule>) -> Result<MmlAst> {
         let ast = match pair.as_rule() {
             Rule::abc_note => {
                 let note_char = pair.clone().as_str().chars().next().unwrap();
@@ -258,13 +278,7 @@ impl MmlAst {
             _ => Err(anyhow::anyhow!("Unknown rule: {:?}", pair)),
         };
 
-        match ast {
-            Ok(ast) => Ok(Some(ast)),
-            Err(e) => {
-                eprintln!("Error parsing command: {:?}", e);
-                Ok(None)
-            }
-        }
+        ast
     }
 }
 
@@ -391,21 +405,14 @@ fn extract_rhythm_macro_define(pair: Pair<Rule>) -> Result<(char, MmlAst)> {
         .ok_or_else(|| anyhow::anyhow!("Expected macro definition"))?;
     let definition = MmlAst::parse_command(definition_pair)?;
 
-    if let Some(definition) = definition {
-        Ok((name, definition))
-    } else {
-        Err(anyhow::anyhow!("Failed to parse macro definition"))
-    }
+    Ok((name, definition))
 }
 
 fn extract_group_notes(pair: Pair<Rule>) -> Result<(Vec<MmlAst>, Option<isize>)> {
     let mut inner_rules = pair.into_inner();
     let mut notes: Vec<MmlAst> = Vec::new();
     for note_pair in inner_rules.by_ref() {
-        let note = MmlAst::parse_command(note_pair)?;
-        if let Some(note) = note {
-            notes.push(note);
-        }
+        notes.push(MmlAst::parse_command(note_pair)?);
     }
     let length = if let Some(length_pair) = inner_rules.next() {
         Some(length_pair.as_str().parse::<isize>()?)
@@ -415,3 +422,27 @@ fn extract_group_notes(pair: Pair<Rule>) -> Result<(Vec<MmlAst>, Option<isize>)>
 
     Ok((notes, length))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+
+    fn rest_node(span: Range<usize>) -> Spanned<MmlAst> {
+        Spanned::new(MmlAst::Rest(None), span)
+    }
+
+    #[test]
+    fn node_at_finds_the_command_whose_span_contains_the_offset() {
+        let ast = vec![rest_node(0..2), rest_node(2..5), rest_node(5..6)];
+
+        let found = MmlAst::node_at(&ast, 3).expect("offset 3 is inside the second node");
+        assert_eq!(found.span, 2..5);
+    }
+
+    #[test]
+    fn node_at_returns_none_once_the_offset_is_past_every_span() {
+        let ast = vec![rest_node(0..2)];
+        assert!(MmlAst::node_at(&ast, 2).is_none());
+    }
+}
@@ -5,6 +5,10 @@ fn main() -> anyhow::Result<()> {
         cdef1,12,1aq100n64,,30,10
     "#;
 
-    println!("{:?}", MmlAst::parse(to_parse)?);
+    let (ast, diagnostics) = MmlAst::parse(to_parse)?;
+    println!("{:?}", ast);
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
     Ok(())
 }
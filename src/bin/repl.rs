@@ -0,0 +1,56 @@
+use mml_parser::{debug_pair, MmlAst, MmlHelper, MmlParser, Rule};
+use pest::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::{Config, Editor};
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::builder().auto_add_history(true).build();
+    let mut editor = Editor::with_config(config)?;
+    editor.set_helper(Some(MmlHelper::new()));
+
+    loop {
+        match editor.readline("mml> ") {
+            Ok(line) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some(source) = line.trim().strip_prefix(":debug ") {
+                    match debug_tree(source) {
+                        Ok(tree) => print!("{tree}"),
+                        Err(e) => eprintln!("{e}"),
+                    }
+                    continue;
+                }
+                match MmlAst::parse(&line) {
+                    Ok((ast, diagnostics)) => {
+                        if let Some(helper) = editor.helper() {
+                            helper.learn(&ast);
+                        }
+                        println!("{:?}", ast);
+                        for diagnostic in &diagnostics {
+                            eprintln!("{}", diagnostic);
+                        }
+                    }
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{e}");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Dump the raw pest parse tree for `input`; handy when the AST itself
+/// doesn't explain why a line didn't validate. Reachable via `:debug <mml>`.
+fn debug_tree(input: &str) -> anyhow::Result<String> {
+    let mut out = String::new();
+    for pair in MmlParser::parse(Rule::mml, input)? {
+        out.push_str(&debug_pair(pair));
+    }
+    Ok(out)
+}
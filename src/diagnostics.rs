@@ -0,0 +1,104 @@
+use pest::error::{Error as PestError, ErrorVariant};
+use pest::iterators::Pair;
+
+use crate::ast::Rule;
+
+/// A single parse failure, carried alongside the AST it was found next to
+/// rather than dropped, so editors and tooling can surface it at the
+/// offending location instead of just a console `eprintln!`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub rule: Rule,
+    pub span: (usize, usize),
+    error: PestError<Rule>,
+}
+
+impl Diagnostic {
+    /// Build a diagnostic for `pair`, rendering `message` as a `pest`
+    /// custom error so it comes with the usual line/column and caret.
+    pub fn from_pair(pair: &Pair<Rule>, message: impl Into<String>) -> Self {
+        let span = pair.as_span();
+        let error = PestError::new_from_span(
+            ErrorVariant::CustomError {
+                message: message.into(),
+            },
+            span,
+        );
+        Diagnostic {
+            rule: pair.as_rule(),
+            span: (span.start(), span.end()),
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// Pretty-print a pest parse tree with one level of indentation per
+/// nesting depth, for debugging grammar matches.
+pub fn debug_pair(pair: Pair<Rule>) -> String {
+    let mut out = String::new();
+    debug_pair_into(pair, 0, &mut out);
+    out
+}
+
+fn debug_pair_into(pair: Pair<Rule>, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(&format!("{:?} {:?}\n", pair.as_rule(), pair.as_str()));
+    for inner in pair.into_inner() {
+        debug_pair_into(inner, depth + 1, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::MmlParser;
+    use pest::Parser;
+
+    fn first_command(input: &str) -> Pair<'static, Rule> {
+        // Leak the parsed string so the returned `Pair` can outlive this
+        // helper; fine for tests, which only ever run a handful of times.
+        let input: &'static str = Box::leak(input.to_string().into_boxed_str());
+        MmlParser::parse(Rule::mml, input)
+            .expect("valid mml")
+            .next()
+            .unwrap()
+            .into_inner()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn from_pair_carries_the_pairs_rule_and_byte_span() {
+        let pair = first_command("c4");
+        let diagnostic = Diagnostic::from_pair(&pair, "example failure");
+        assert_eq!(diagnostic.rule, pair.as_rule());
+        assert_eq!(diagnostic.span, (pair.as_span().start(), pair.as_span().end()));
+    }
+
+    #[test]
+    fn display_renders_the_message_pest_gave_it() {
+        let pair = first_command("c4");
+        let diagnostic = Diagnostic::from_pair(&pair, "example failure");
+        assert!(diagnostic.to_string().contains("example failure"));
+    }
+
+    #[test]
+    fn debug_pair_indents_children_one_level_deeper_than_their_parent() {
+        let pair = first_command("c4");
+        let rendered = debug_pair(pair);
+        let mut lines = rendered.lines();
+        let first = lines.next().expect("at least one line");
+        assert!(!first.starts_with(' '));
+        for line in lines {
+            assert!(line.starts_with("  "), "child line not indented: {line:?}");
+        }
+    }
+}
@@ -0,0 +1,13 @@
+pub mod ast;
+pub mod diagnostics;
+pub mod lower;
+pub mod repl;
+pub mod smf;
+pub mod span;
+
+pub use ast::{CommentKind, MmlAst, MmlParser, Rule};
+pub use diagnostics::{debug_pair, Diagnostic};
+pub use lower::{lower, lower_with_options, LowerOptions, TimedEvent, DEFAULT_PPQN};
+pub use repl::MmlHelper;
+pub use smf::{to_smf, SmfFormat, SmfOptions};
+pub use span::Spanned;
@@ -0,0 +1,522 @@
+//! Lowers the stateful, relative [`MmlAst`] stream into a flat list of
+//! absolute-tick MIDI-shaped events. This is the bridge between the parser
+//! and any downstream renderer (e.g. an SMF writer): everything here is
+//! still data, no byte-level MIDI encoding happens in this module.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::ast::MmlAst;
+use crate::span::Spanned;
+
+/// Pulses (ticks) per quarter note. A length of `4` maps to one `PPQN`
+/// worth of ticks, `8` to `PPQN / 2`, and so on.
+pub const DEFAULT_PPQN: isize = 480;
+
+/// How many times a bare `[ ... ]` loop (no explicit count) repeats.
+const DEFAULT_LOOP_COUNT: isize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LowerOptions {
+    pub ppqn: isize,
+}
+
+impl Default for LowerOptions {
+    fn default() -> Self {
+        LowerOptions { ppqn: DEFAULT_PPQN }
+    }
+}
+
+/// A MIDI-shaped event with an absolute tick timestamp, ready for
+/// serialization (e.g. to a Standard MIDI File).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimedEvent {
+    NoteOn {
+        tick: isize,
+        note: isize,
+        velocity: isize,
+        velocity_random: Option<isize>,
+        timing_random: Option<isize>,
+    },
+    NoteOff {
+        tick: isize,
+        note: isize,
+    },
+    ControlChange {
+        tick: isize,
+        controller: isize,
+        value: isize,
+    },
+    ProgramChange {
+        tick: isize,
+        program: isize,
+        bank_lsb: Option<isize>,
+        bank_msb: Option<isize>,
+    },
+    PitchBend {
+        tick: isize,
+        value: isize,
+    },
+}
+
+/// Flatten `ast` into an absolute-timed event stream using the default
+/// PPQN. See [`lower_with_options`] to customize it.
+pub fn lower(ast: &[Spanned<MmlAst>]) -> Result<Vec<TimedEvent>> {
+    lower_with_options(ast, LowerOptions::default())
+}
+
+pub fn lower_with_options(ast: &[Spanned<MmlAst>], opts: LowerOptions) -> Result<Vec<TimedEvent>> {
+    let begin_to_end = match_loops(ast)?;
+    let mut cursor = Cursor::new(opts.ppqn);
+    let mut events = Vec::new();
+    let mut loop_stack: Vec<LoopFrame> = Vec::new();
+
+    let mut pc = 0usize;
+    while pc < ast.len() {
+        match &ast[pc].node {
+            MmlAst::LoopBegin(count) => {
+                let end = begin_to_end[&pc];
+                loop_stack.push(LoopFrame {
+                    start: pc,
+                    end,
+                    remaining: count.unwrap_or(DEFAULT_LOOP_COUNT),
+                });
+                pc += 1;
+            }
+            MmlAst::LoopBreak => {
+                if let Some(frame) = loop_stack.last() {
+                    if frame.remaining <= 1 {
+                        pc = frame.end + 1;
+                        loop_stack.pop();
+                        continue;
+                    }
+                }
+                pc += 1;
+            }
+            MmlAst::LoopEnd => {
+                let mut frame = loop_stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("LoopEnd without matching LoopBegin at index {pc}"))?;
+                frame.remaining -= 1;
+                if frame.remaining > 0 {
+                    pc = frame.start + 1;
+                    loop_stack.push(frame);
+                } else {
+                    pc = frame.end + 1;
+                }
+            }
+            node => {
+                cursor.lower_node(node, &mut events)?;
+                pc += 1;
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+/// Precompute the index of the matching `LoopEnd` for every `LoopBegin`.
+fn match_loops(ast: &[Spanned<MmlAst>]) -> Result<HashMap<usize, usize>> {
+    let mut map = HashMap::new();
+    let mut stack = Vec::new();
+    for (i, node) in ast.iter().enumerate() {
+        match &node.node {
+            MmlAst::LoopBegin(_) => stack.push(i),
+            MmlAst::LoopEnd => {
+                let begin = stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("unmatched LoopEnd at index {i}"))?;
+                map.insert(begin, i);
+            }
+            _ => {}
+        }
+    }
+    if let Some(begin) = stack.pop() {
+        return Err(anyhow!("unmatched LoopBegin at index {begin}"));
+    }
+    Ok(map)
+}
+
+struct LoopFrame {
+    start: usize,
+    end: usize,
+    remaining: isize,
+}
+
+/// Interpreter state threaded through the flattening walk.
+struct Cursor {
+    ppqn: isize,
+    tick: isize,
+    octave: isize,
+    octave_once: Option<isize>,
+    default_length: isize,
+    velocity: isize,
+    velocity_random: Option<isize>,
+    timing_random: Option<isize>,
+    gate: Option<isize>,
+    tie_pending: bool,
+    last_note_off: Option<(isize, usize)>,
+}
+
+impl Cursor {
+    fn new(ppqn: isize) -> Self {
+        Cursor {
+            ppqn,
+            tick: 0,
+            octave: 4,
+            octave_once: None,
+            default_length: 4,
+            velocity: 100,
+            velocity_random: None,
+            timing_random: None,
+            gate: None,
+            tie_pending: false,
+            last_note_off: None,
+        }
+    }
+
+    fn duration_ticks(&self, length: isize) -> isize {
+        if length <= 0 {
+            self.ppqn
+        } else {
+            self.ppqn * 4 / length
+        }
+    }
+
+    fn note_duration(&self, length: Option<isize>) -> isize {
+        self.duration_ticks(length.unwrap_or(self.default_length))
+    }
+
+    fn current_octave(&mut self) -> isize {
+        match self.octave_once.take() {
+            Some(shift) => self.octave + shift,
+            None => self.octave,
+        }
+    }
+
+    fn lower_node(&mut self, node: &MmlAst, events: &mut Vec<TimedEvent>) -> Result<()> {
+        match node {
+            MmlAst::Note {
+                note,
+                length,
+                velocity,
+                gate,
+                scale,
+                ..
+            } => {
+                let octave = self.current_octave();
+                let pitch = octave * 12 + semitone(*note) + scale.unwrap_or(0);
+                let duration = self.note_duration(*length);
+                self.emit_note(pitch, duration, *velocity, *gate, events);
+            }
+            MmlAst::NNote {
+                note_no,
+                length,
+                velocity,
+                gate,
+                ..
+            } => {
+                let duration = self.note_duration(*length);
+                self.emit_note(*note_no, duration, *velocity, *gate, events);
+            }
+            MmlAst::Rest(length) => {
+                let duration = self.note_duration(*length);
+                self.tick += duration;
+                self.tie_pending = false;
+            }
+            MmlAst::Length(length) => self.default_length = *length,
+            MmlAst::Octave(octave) => self.octave = *octave,
+            MmlAst::OctaveUp => self.octave += 1,
+            MmlAst::OctaveDown => self.octave -= 1,
+            MmlAst::OctaveUpOnce => self.octave_once = Some(1),
+            MmlAst::OctaveDownOnce => self.octave_once = Some(-1),
+            MmlAst::Velocity { value, random } => {
+                self.velocity = *value;
+                self.velocity_random = *random;
+            }
+            MmlAst::VelocityUp(amount) => self.velocity += amount.unwrap_or(1),
+            MmlAst::VelocityDown(amount) => self.velocity -= amount.unwrap_or(1),
+            MmlAst::Timing { random, .. } => self.timing_random = *random,
+            MmlAst::Gate(value) => self.gate = Some(*value),
+            MmlAst::PitchBend(value) => events.push(TimedEvent::PitchBend {
+                tick: self.tick,
+                value: *value,
+            }),
+            MmlAst::ControlChange {
+                controller, value, ..
+            } => events.push(TimedEvent::ControlChange {
+                tick: self.tick,
+                controller: *controller,
+                value: *value,
+            }),
+            MmlAst::VoiceSelect {
+                number,
+                bank_lsb,
+                bank_msb,
+            } => events.push(TimedEvent::ProgramChange {
+                tick: self.tick,
+                program: *number,
+                bank_lsb: *bank_lsb,
+                bank_msb: *bank_msb,
+            }),
+            MmlAst::TieSlur => self.tie_pending = true,
+            MmlAst::Harmony {
+                notes,
+                length,
+                gate,
+            } => {
+                let octave = self.current_octave();
+                let duration = self.note_duration(*length);
+                let sounding = apply_gate(duration, gate.or(self.gate));
+                let start = self.tick;
+                for note in notes {
+                    let pitch = octave * 12 + semitone(*note);
+                    events.push(TimedEvent::NoteOn {
+                        tick: start,
+                        note: pitch,
+                        velocity: self.velocity,
+                        velocity_random: self.velocity_random,
+                        timing_random: self.timing_random,
+                    });
+                }
+                for note in notes {
+                    let pitch = octave * 12 + semitone(*note);
+                    events.push(TimedEvent::NoteOff {
+                        tick: start + sounding,
+                        note: pitch,
+                    });
+                }
+                self.tick = start + duration;
+                self.tie_pending = false;
+                // A chord has no single pitch to tie into, so drop any
+                // leftover single-note state rather than letting a later
+                // tie match whatever note happened to play before this.
+                self.last_note_off = None;
+            }
+            MmlAst::GroupedNotes { notes, length } => {
+                if !notes.is_empty() {
+                    let total = self.note_duration(*length);
+                    let per_note = total / notes.len() as isize;
+                    for note in notes {
+                        self.lower_tuplet_note(note, per_note, events)?;
+                    }
+                }
+            }
+            // Macros and source-only markers don't produce events on their own.
+            MmlAst::Macro(_)
+            | MmlAst::RhythmMacroDefine { .. }
+            | MmlAst::Comment { .. }
+            | MmlAst::PlayFromHere => {}
+            MmlAst::LoopBegin(_) | MmlAst::LoopBreak | MmlAst::LoopEnd => {
+                return Err(anyhow!(
+                    "loop constructs are only valid at the top level, not nested inside a tuplet or other node"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Lower a single element of a tuplet (`GroupedNotes`), whose duration
+    /// is fixed by splitting the group's length evenly rather than by the
+    /// element's own length field.
+    fn lower_tuplet_note(
+        &mut self,
+        node: &MmlAst,
+        duration: isize,
+        events: &mut Vec<TimedEvent>,
+    ) -> Result<()> {
+        match node {
+            MmlAst::Note {
+                note,
+                velocity,
+                gate,
+                scale,
+                ..
+            } => {
+                let octave = self.current_octave();
+                let pitch = octave * 12 + semitone(*note) + scale.unwrap_or(0);
+                self.emit_note(pitch, duration, *velocity, *gate, events);
+                Ok(())
+            }
+            MmlAst::NNote {
+                note_no,
+                velocity,
+                gate,
+                ..
+            } => {
+                self.emit_note(*note_no, duration, *velocity, *gate, events);
+                Ok(())
+            }
+            MmlAst::Rest(_) => {
+                self.tick += duration;
+                self.tie_pending = false;
+                Ok(())
+            }
+            other => self.lower_node(other, events),
+        }
+    }
+
+    fn emit_note(
+        &mut self,
+        pitch: isize,
+        duration: isize,
+        velocity: Option<isize>,
+        gate: Option<isize>,
+        events: &mut Vec<TimedEvent>,
+    ) {
+        let velocity = velocity.unwrap_or(self.velocity);
+        let sounding = apply_gate(duration, gate.or(self.gate));
+        let start = self.tick;
+        let end = start + sounding;
+
+        if self.tie_pending {
+            if let Some((last_pitch, noteoff_idx)) = self.last_note_off {
+                if last_pitch == pitch {
+                    events.remove(noteoff_idx);
+                    events.push(TimedEvent::NoteOff { tick: end, note: pitch });
+                    self.last_note_off = Some((pitch, events.len() - 1));
+                    self.tick = start + duration;
+                    self.tie_pending = false;
+                    return;
+                }
+            }
+            self.tie_pending = false;
+        }
+
+        events.push(TimedEvent::NoteOn {
+            tick: start,
+            note: pitch,
+            velocity,
+            velocity_random: self.velocity_random,
+            timing_random: self.timing_random,
+        });
+        events.push(TimedEvent::NoteOff { tick: end, note: pitch });
+        self.last_note_off = Some((pitch, events.len() - 1));
+        self.tick = start + duration;
+    }
+}
+
+fn apply_gate(duration: isize, gate: Option<isize>) -> isize {
+    match gate {
+        Some(percent) => duration * percent / 100,
+        None => duration,
+    }
+}
+
+fn semitone(note: char) -> isize {
+    match note.to_ascii_lowercase() {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(ch: char) -> MmlAst {
+        MmlAst::Note {
+            note: ch,
+            length: None,
+            gate: None,
+            velocity: None,
+            timing: None,
+            scale: None,
+        }
+    }
+
+    fn spanned(node: MmlAst) -> Spanned<MmlAst> {
+        Spanned::new(node, 0..0)
+    }
+
+    fn note_ons(events: &[TimedEvent]) -> Vec<isize> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                TimedEvent::NoteOn { note, .. } => Some(*note),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_break_skips_the_tail_only_on_the_final_iteration() {
+        let ast = vec![
+            spanned(MmlAst::LoopBegin(Some(3))),
+            spanned(note('c')),
+            spanned(MmlAst::LoopBreak),
+            spanned(note('d')),
+            spanned(MmlAst::LoopEnd),
+        ];
+        let events = lower(&ast).unwrap();
+        let pitches = note_ons(&events);
+        let c = 4 * 12 + semitone('c');
+        let d = 4 * 12 + semitone('d');
+        // 3 full passes of `c`, but `d` only plays on the first two: the
+        // final pass hits the break and skips straight past LoopEnd.
+        assert_eq!(pitches, vec![c, d, c, d, c]);
+    }
+
+    #[test]
+    fn tie_does_not_reach_across_a_harmony_chord() {
+        let ast = vec![
+            spanned(note('c')),
+            spanned(MmlAst::TieSlur),
+            spanned(MmlAst::Harmony {
+                notes: vec!['c', 'e'],
+                length: None,
+                gate: None,
+            }),
+            spanned(MmlAst::TieSlur),
+            spanned(note('c')),
+        ];
+        let events = lower(&ast).unwrap();
+
+        let note_on_count = events
+            .iter()
+            .filter(|e| matches!(e, TimedEvent::NoteOn { .. }))
+            .count();
+        let note_off_count = events
+            .iter()
+            .filter(|e| matches!(e, TimedEvent::NoteOff { .. }))
+            .count();
+        // 1 (leading note) + 2 (chord) + 1 (trailing note), none merged away.
+        assert_eq!(note_on_count, 4);
+        assert_eq!(note_off_count, 4);
+    }
+
+    #[test]
+    fn tuplet_splits_its_length_evenly_across_its_notes() {
+        let ast = vec![spanned(MmlAst::GroupedNotes {
+            notes: vec![note('c'), note('d'), note('e')],
+            length: Some(4),
+        })];
+        let events = lower(&ast).unwrap();
+
+        let starts: Vec<isize> = events
+            .iter()
+            .filter_map(|e| match e {
+                TimedEvent::NoteOn { tick, .. } => Some(*tick),
+                _ => None,
+            })
+            .collect();
+        let per_note = DEFAULT_PPQN / 3;
+        assert_eq!(starts, vec![0, per_note, per_note * 2]);
+    }
+
+    #[test]
+    fn loop_construct_nested_in_a_tuplet_is_an_error_not_a_panic() {
+        let ast = vec![spanned(MmlAst::GroupedNotes {
+            notes: vec![MmlAst::LoopBegin(Some(2))],
+            length: None,
+        })];
+        assert!(lower(&ast).is_err());
+    }
+}
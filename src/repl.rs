@@ -0,0 +1,273 @@
+//! A `rustyline`-backed REPL helper for MML: validates multi-line input
+//! (unbalanced loop/group brackets, open range comments), highlights
+//! tokens using the grammar's own rule boundaries, and completes on
+//! macro names defined earlier in the session.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use pest::Parser;
+use rustyline::completion::{Completer, Pair as CompletionPair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+use crate::ast::{MmlAst, MmlParser as Grammar, Rule};
+use crate::span::Spanned;
+
+const COMMAND_LETTERS: &[&str] = &[
+    "c", "d", "e", "f", "g", "a", "b", "n", "l", "o", "q", "v", "t", "p", "k", "@", "[", "]", "|",
+];
+
+pub struct MmlHelper {
+    hinter: HistoryHinter,
+    macros: RefCell<BTreeSet<String>>,
+}
+
+impl MmlHelper {
+    pub fn new() -> Self {
+        MmlHelper {
+            hinter: HistoryHinter::new(),
+            macros: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// Record the rhythm macro names (`RhythmMacroDefine`) defined in a
+    /// parsed line so they show up in later completions. `Macro` nodes are
+    /// not recorded.
+    pub fn learn(&self, ast: &[Spanned<MmlAst>]) {
+        let mut macros = self.macros.borrow_mut();
+        for node in ast {
+            if let MmlAst::RhythmMacroDefine { name, .. } = &node.node {
+                macros.insert(name.to_string());
+            }
+        }
+    }
+}
+
+impl Default for MmlHelper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator for MmlHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if needs_more_input(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+/// Whether `input` still has an unbalanced loop/group bracket or an open
+/// range comment, i.e. the REPL should keep reading lines instead of
+/// submitting. Split out from [`Validator::validate`] so it can be tested
+/// without a `rustyline` `ValidationContext`.
+fn needs_more_input(input: &str) -> bool {
+    let mut loop_depth = 0i32;
+    let mut group_depth = 0i32;
+    let mut in_range_comment = false;
+
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '[' if !in_range_comment => loop_depth += 1,
+            ']' if !in_range_comment => loop_depth -= 1,
+            '{' if !in_range_comment => group_depth += 1,
+            '}' if !in_range_comment => group_depth -= 1,
+            '/' if chars.peek() == Some(&'*') && !in_range_comment => {
+                chars.next();
+                in_range_comment = true;
+            }
+            '*' if chars.peek() == Some(&'/') && in_range_comment => {
+                chars.next();
+                in_range_comment = false;
+            }
+            _ => {}
+        }
+    }
+
+    in_range_comment || loop_depth > 0 || group_depth > 0
+}
+
+impl Highlighter for MmlHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let Ok(pairs) = Grammar::parse(Rule::mml, line) else {
+            return Cow::Borrowed(line);
+        };
+
+        let mut spans: Vec<(usize, usize, &'static str)> = Vec::new();
+        for pair in pairs.flatten() {
+            let color = match pair.as_rule() {
+                Rule::abc_note | Rule::midi_note => Some("\x1b[36m"),
+                Rule::length | Rule::octave => Some("\x1b[33m"),
+                Rule::loop_begin | Rule::loop_end | Rule::loop_break => Some("\x1b[35m"),
+                Rule::range_comment | Rule::line_comment | Rule::line_comment_debug => {
+                    Some("\x1b[90m")
+                }
+                _ => None,
+            };
+            if let Some(color) = color {
+                let span = pair.as_span();
+                spans.push((span.start(), span.end(), color));
+            }
+        }
+        spans.sort_by_key(|(start, ..)| *start);
+
+        let mut out = String::with_capacity(line.len() + spans.len() * 8);
+        let mut cursor = 0;
+        for (start, end, color) in spans {
+            if start < cursor {
+                continue;
+            }
+            out.push_str(&line[cursor..start]);
+            out.push_str(color);
+            out.push_str(&line[start..end]);
+            out.push_str("\x1b[0m");
+            cursor = end;
+        }
+        out.push_str(&line[cursor..]);
+        Cow::Owned(out)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        true
+    }
+}
+
+impl Hinter for MmlHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        self.hinter.hint(line, pos, ctx)
+    }
+}
+
+impl Completer for MmlHelper {
+    type Candidate = CompletionPair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<CompletionPair>)> {
+        Ok(self.complete_at(line, pos))
+    }
+}
+
+impl MmlHelper {
+    /// The actual completion logic, split out from [`Completer::complete`]
+    /// so it can be tested without a `rustyline` `Context`.
+    fn complete_at(&self, line: &str, pos: usize) -> (usize, Vec<CompletionPair>) {
+        let start = line[..pos]
+            .rfind(|c: char| !c.is_alphanumeric())
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() {
+            return (start, Vec::new());
+        }
+
+        let mut candidates: Vec<CompletionPair> = self
+            .macros
+            .borrow()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| CompletionPair {
+                display: format!("{name} (macro)"),
+                replacement: name.clone(),
+            })
+            .collect();
+
+        candidates.extend(
+            COMMAND_LETTERS
+                .iter()
+                .filter(|letter| letter.starts_with(prefix))
+                .map(|letter| CompletionPair {
+                    display: letter.to_string(),
+                    replacement: letter.to_string(),
+                }),
+        );
+
+        (start, candidates)
+    }
+}
+
+impl Helper for MmlHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balanced_input_does_not_need_more_input() {
+        assert!(!needs_more_input("cdefgab"));
+        assert!(!needs_more_input("[cde]4"));
+        assert!(!needs_more_input("{cde}"));
+        assert!(!needs_more_input("/* a comment */cde"));
+    }
+
+    #[test]
+    fn an_open_loop_or_group_bracket_needs_more_input() {
+        assert!(needs_more_input("[cde"));
+        assert!(needs_more_input("{cde"));
+        assert!(needs_more_input("[[cde]")); // one `[` still unmatched
+    }
+
+    #[test]
+    fn an_unterminated_range_comment_needs_more_input() {
+        assert!(needs_more_input("/* still open"));
+        assert!(!needs_more_input("/* closed */"));
+    }
+
+    #[test]
+    fn brackets_inside_a_range_comment_are_ignored() {
+        assert!(!needs_more_input("/* [ { unbalanced in here */"));
+    }
+
+    #[test]
+    fn complete_at_suggests_known_macros_and_command_letters() {
+        let helper = MmlHelper::new();
+        helper.macros.borrow_mut().insert("foo".to_string());
+        helper.macros.borrow_mut().insert("bar".to_string());
+
+        let (start, candidates) = helper.complete_at("fo", 2);
+        assert_eq!(start, 0);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].replacement, "foo");
+    }
+
+    #[test]
+    fn complete_at_returns_nothing_for_an_empty_prefix() {
+        let helper = MmlHelper::new();
+        let (_, candidates) = helper.complete_at("cde ", 4);
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn learn_records_rhythm_macro_names_for_later_completion() {
+        let helper = MmlHelper::new();
+        let ast = vec![Spanned::new(
+            MmlAst::RhythmMacroDefine {
+                name: 'x',
+                definition: Box::new(MmlAst::Rest(None)),
+            },
+            0..3,
+        )];
+        helper.learn(&ast);
+
+        let (_, candidates) = helper.complete_at("x", 1);
+        assert!(candidates.iter().any(|c| c.replacement == "x"));
+    }
+
+    #[test]
+    fn highlight_leaves_an_empty_line_untouched() {
+        let helper = MmlHelper::new();
+        assert_eq!(helper.highlight("", 0), Cow::Borrowed(""));
+    }
+}
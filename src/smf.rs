@@ -0,0 +1,415 @@
+//! Serializes a [`TimedEvent`] stream to a Standard MIDI File.
+//!
+//! The crate only ever produces a single logical channel of events today
+//! (there is no per-track concept in [`MmlAst`]), so [`SmfFormat::MultiTrack`]
+//! currently still writes a single `MTrk`; the distinction only changes the
+//! `MThd` format byte. It is kept separate from [`SmfFormat::SingleTrack`]
+//! so callers get the header they asked for.
+
+use crate::lower::TimedEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmfFormat {
+    SingleTrack,
+    MultiTrack,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SmfOptions {
+    pub format: SmfFormat,
+    pub ppqn: isize,
+    pub channel: u8,
+    /// Seed for jittering `Velocity { random }`/`Timing { random }` fields.
+    /// `None` disables jitter and serializes the nominal values.
+    pub jitter_seed: Option<u64>,
+}
+
+impl Default for SmfOptions {
+    fn default() -> Self {
+        SmfOptions {
+            format: SmfFormat::SingleTrack,
+            ppqn: crate::lower::DEFAULT_PPQN,
+            channel: 0,
+            jitter_seed: None,
+        }
+    }
+}
+
+pub fn to_smf(events: &[TimedEvent], opts: SmfOptions) -> Vec<u8> {
+    let mut rng = opts.jitter_seed.map(SplitMix64::new);
+    // Jitter first, *then* sort by the tick each event will actually be
+    // written at — sorting by the nominal tick and jittering while writing
+    // would let a jittered event's delta-time go negative and silently
+    // clamp to zero whenever jitter reorders two events.
+    let mut jittered: Vec<(isize, TimedEvent)> = events
+        .iter()
+        .cloned()
+        .map(|event| jitter_event(event, &mut rng))
+        .collect();
+    jittered.sort_by_key(|(tick, _)| *tick);
+
+    // Jitter can push the earliest event before tick 0. Shift the whole
+    // stream so it starts at 0 instead of letting `write_track` clamp the
+    // resulting negative delta to 0, which would desync every tick after it.
+    if let Some(&(min_tick, _)) = jittered.first() {
+        if min_tick < 0 {
+            for (tick, _) in jittered.iter_mut() {
+                *tick -= min_tick;
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"MThd");
+    write_u32(&mut out, 6);
+    write_u16(&mut out, format_byte(opts.format));
+    write_u16(&mut out, 1); // ntrks
+    write_u16(&mut out, opts.ppqn as u16);
+
+    let track = write_track(&jittered, opts.channel);
+    out.extend_from_slice(b"MTrk");
+    write_u32(&mut out, track.len() as u32);
+    out.extend_from_slice(&track);
+
+    out
+}
+
+/// Apply timing/velocity jitter once, up front, pairing the event with the
+/// tick it will be written at.
+fn jitter_event(mut event: TimedEvent, rng: &mut Option<SplitMix64>) -> (isize, TimedEvent) {
+    let tick = jittered_tick(&event, rng);
+    if let TimedEvent::NoteOn {
+        velocity,
+        velocity_random,
+        ..
+    } = &mut event
+    {
+        *velocity = jittered_velocity(*velocity, *velocity_random, rng);
+    }
+    (tick, event)
+}
+
+fn format_byte(format: SmfFormat) -> u16 {
+    match format {
+        SmfFormat::SingleTrack => 0,
+        SmfFormat::MultiTrack => 1,
+    }
+}
+
+fn write_track(events: &[(isize, TimedEvent)], channel: u8) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut last_tick = 0isize;
+    let mut running_status: Option<u8> = None;
+
+    for (tick, event) in events {
+        let tick = *tick;
+        // `events` arrives sorted (and shifted to start at 0) by `to_smf`,
+        // so every delta here is non-negative by construction.
+        let delta = tick - last_tick;
+        last_tick = tick;
+        write_vlq(&mut out, delta as u32);
+
+        match event {
+            TimedEvent::NoteOn { note, velocity, .. } => {
+                write_channel_event(&mut out, &mut running_status, 0x90, channel, clamp_note(*note), *velocity as u8);
+            }
+            TimedEvent::NoteOff { note, .. } => {
+                write_channel_event(&mut out, &mut running_status, 0x80, channel, clamp_note(*note), 0);
+            }
+            TimedEvent::ControlChange {
+                controller, value, ..
+            } => {
+                write_channel_event(
+                    &mut out,
+                    &mut running_status,
+                    0xB0,
+                    channel,
+                    *controller as u8,
+                    *value as u8,
+                );
+            }
+            TimedEvent::ProgramChange {
+                program,
+                bank_lsb,
+                bank_msb,
+                ..
+            } => {
+                // The leading delta-time for this whole group was already
+                // written above; every sub-event after the first needs its
+                // own (zero, since they're simultaneous) delta-time.
+                let mut first = true;
+                for (controller, value) in [(0u8, bank_msb), (32u8, bank_lsb)] {
+                    if let Some(value) = value {
+                        if !first {
+                            write_vlq(&mut out, 0);
+                        }
+                        first = false;
+                        write_channel_event(&mut out, &mut running_status, 0xB0, channel, controller, *value as u8);
+                    }
+                }
+                if !first {
+                    write_vlq(&mut out, 0);
+                }
+                write_program_change(&mut out, &mut running_status, channel, *program as u8);
+            }
+            TimedEvent::PitchBend { value, .. } => {
+                let bent = (*value + 8192).clamp(0, 16383) as u16;
+                let lsb = (bent & 0x7F) as u8;
+                let msb = ((bent >> 7) & 0x7F) as u8;
+                write_channel_event(&mut out, &mut running_status, 0xE0, channel, lsb, msb);
+            }
+        }
+    }
+
+    write_vlq(&mut out, 0);
+    out.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+    out
+}
+
+fn write_channel_event(
+    out: &mut Vec<u8>,
+    running_status: &mut Option<u8>,
+    kind: u8,
+    channel: u8,
+    data1: u8,
+    data2: u8,
+) {
+    let status = kind | (channel & 0x0F);
+    if *running_status != Some(status) {
+        out.push(status);
+        *running_status = Some(status);
+    }
+    out.push(data1 & 0x7F);
+    out.push(data2 & 0x7F);
+}
+
+fn write_program_change(out: &mut Vec<u8>, running_status: &mut Option<u8>, channel: u8, program: u8) {
+    let status = 0xC0 | (channel & 0x0F);
+    if *running_status != Some(status) {
+        out.push(status);
+        *running_status = Some(status);
+    }
+    out.push(program & 0x7F);
+}
+
+fn event_tick(event: &TimedEvent) -> isize {
+    match event {
+        TimedEvent::NoteOn { tick, .. }
+        | TimedEvent::NoteOff { tick, .. }
+        | TimedEvent::ControlChange { tick, .. }
+        | TimedEvent::ProgramChange { tick, .. }
+        | TimedEvent::PitchBend { tick, .. } => *tick,
+    }
+}
+
+fn jittered_tick(event: &TimedEvent, rng: &mut Option<SplitMix64>) -> isize {
+    let tick = event_tick(event);
+    match (event, rng) {
+        (TimedEvent::NoteOn { timing_random: Some(range), .. }, Some(rng)) if *range != 0 => {
+            tick + rng.next_signed(*range)
+        }
+        _ => tick,
+    }
+}
+
+/// MIDI note numbers are a 7-bit field. `lower.rs` leaves octave shifts
+/// unbounded, so a note can end up outside `0..=127` by the time it gets
+/// here; clamp it the same way `jittered_velocity` clamps velocity instead
+/// of silently wrapping into an unrelated pitch.
+fn clamp_note(note: isize) -> u8 {
+    note.clamp(0, 127) as u8
+}
+
+fn jittered_velocity(velocity: isize, random: Option<isize>, rng: &mut Option<SplitMix64>) -> isize {
+    let velocity = match (random, rng) {
+        (Some(range), Some(rng)) if range != 0 => velocity + rng.next_signed(range),
+        _ => velocity,
+    };
+    velocity.clamp(0, 127)
+}
+
+fn write_vlq(out: &mut Vec<u8>, value: u32) {
+    let mut buffer = value & 0x7F;
+    let mut value = value >> 7;
+    while value > 0 {
+        buffer <<= 8;
+        buffer |= 0x80 | (value & 0x7F);
+        value >>= 7;
+    }
+    loop {
+        out.push((buffer & 0xFF) as u8);
+        if buffer & 0x80 == 0 {
+            break;
+        }
+        buffer >>= 8;
+    }
+}
+
+fn write_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// A tiny, dependency-free seeded PRNG used only to humanize timing and
+/// velocity; not cryptographic, not shared with anything outside this file.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform offset in `[-range, range]`.
+    fn next_signed(&mut self, range: isize) -> isize {
+        if range == 0 {
+            return 0;
+        }
+        let span = (range.unsigned_abs() as u64) * 2 + 1;
+        let value = (self.next_u64() % span) as isize;
+        value - range.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(tick: isize, note: isize, timing_random: Option<isize>) -> TimedEvent {
+        TimedEvent::NoteOn {
+            tick,
+            note,
+            velocity: 100,
+            velocity_random: None,
+            timing_random,
+        }
+    }
+
+    fn read_vlq(bytes: &[u8], pos: &mut usize) -> u32 {
+        let mut value = 0u32;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn vlq_matches_known_midi_encodings() {
+        let cases: &[(u32, &[u8])] = &[
+            (0x0000_0000, &[0x00]),
+            (0x0000_0040, &[0x40]),
+            (0x0000_007F, &[0x7F]),
+            (0x0000_0080, &[0x81, 0x00]),
+            (0x0000_2000, &[0xC0, 0x00]),
+            (0x0000_3FFF, &[0xFF, 0x7F]),
+        ];
+        for &(value, expected) in cases {
+            let mut out = Vec::new();
+            write_vlq(&mut out, value);
+            assert_eq!(out, expected, "encoding {value:#x}");
+
+            let mut pos = 0;
+            assert_eq!(read_vlq(&out, &mut pos), value, "decoding {value:#x}");
+        }
+    }
+
+    #[test]
+    fn mthd_and_mtrk_chunks_have_the_expected_shape() {
+        let events = vec![note_on(0, 60, None), TimedEvent::NoteOff { tick: 480, note: 60 }];
+        let bytes = to_smf(&events, SmfOptions::default());
+
+        assert_eq!(&bytes[0..4], b"MThd");
+        assert_eq!(u32::from_be_bytes(bytes[4..8].try_into().unwrap()), 6);
+        assert_eq!(u16::from_be_bytes(bytes[8..10].try_into().unwrap()), 0); // format 0
+        assert_eq!(u16::from_be_bytes(bytes[10..12].try_into().unwrap()), 1); // ntrks
+        assert_eq!(
+            u16::from_be_bytes(bytes[12..14].try_into().unwrap()),
+            crate::lower::DEFAULT_PPQN as u16
+        );
+        assert_eq!(&bytes[14..18], b"MTrk");
+        assert_eq!(&bytes[bytes.len() - 3..], &[0xFF, 0x2F, 0x00]);
+    }
+
+    #[test]
+    fn running_status_elides_the_repeated_status_byte() {
+        let events = vec![(0, note_on(0, 60, None)), (10, note_on(10, 64, None))];
+        let track = write_track(&events, 0);
+
+        assert_eq!(&track[..4], &[0x00, 0x90, 60, 100]);
+        // Same status (note-on, channel 0) as the previous event: no
+        // repeated 0x90 byte, just delta + note + velocity.
+        assert_eq!(&track[4..7], &[0x0A, 64, 100]);
+    }
+
+    #[test]
+    fn jittered_ticks_are_sorted_before_deltas_are_computed() {
+        let events = vec![note_on(0, 60, Some(20)), note_on(10, 64, Some(20))];
+
+        // These seeds are known (from manual search) to jitter the two
+        // events out of their nominal order.
+        for seed in [100u64, 103, 109] {
+            let mut rng = Some(SplitMix64::new(seed));
+            let mut expected: Vec<(isize, TimedEvent)> = events
+                .iter()
+                .cloned()
+                .map(|event| jitter_event(event, &mut rng))
+                .collect();
+            expected.sort_by_key(|(tick, _)| *tick);
+            let shift = expected.first().map(|(tick, _)| (*tick).min(0)).unwrap_or(0);
+            let expected_ticks: Vec<isize> =
+                expected.iter().map(|(tick, _)| *tick - shift).collect();
+
+            let opts = SmfOptions {
+                jitter_seed: Some(seed),
+                ..SmfOptions::default()
+            };
+            let bytes = to_smf(&events, opts);
+
+            // MThd (14 bytes) + "MTrk" + length (4 bytes) = track data start.
+            let mut pos = 14 + 8;
+            let mut last_tick = 0isize;
+            let mut actual_ticks = Vec::new();
+            for _ in 0..expected.len() {
+                let delta = read_vlq(&bytes, &mut pos) as isize;
+                last_tick += delta;
+                actual_ticks.push(last_tick);
+                if bytes[pos] & 0x80 != 0 {
+                    pos += 1; // a fresh status byte, skip it
+                }
+                pos += 2; // note, velocity
+            }
+
+            assert_eq!(actual_ticks, expected_ticks, "seed {seed}");
+            // The whole point: no event's delta-time got clamped to zero
+            // because jitter reordered it past a neighbor that sorting
+            // never saw.
+            assert!(actual_ticks.windows(2).all(|w| w[0] <= w[1]));
+        }
+    }
+
+    #[test]
+    fn out_of_range_pitches_are_clamped_not_wrapped() {
+        let events = vec![(0, note_on(0, 200, None)), (0, note_on(0, -20, None))];
+        let track = write_track(&events, 0);
+
+        assert_eq!(track[2], 127); // 200 clamped down, not wrapped to 72
+        assert_eq!(track[5], 0); // -20 clamped up, not wrapped to a positive byte
+    }
+}
@@ -0,0 +1,85 @@
+//! Source-location bookkeeping for the AST. [`Spanned`] pairs a node with
+//! the byte range it was parsed from, so editor integrations (go-to-source,
+//! inline squiggles, range-based refactors) can map back from the AST to
+//! the original text without re-parsing.
+
+use std::ops::{Deref, Range};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Range<usize>,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Range<usize>) -> Self {
+        Spanned { node, span }
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        self.span.contains(&offset)
+    }
+
+    /// 1-based (line, column) of the span's start within `input`.
+    pub fn line_col(&self, input: &str) -> (usize, usize) {
+        line_col_at(input, self.span.start)
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+pub fn line_col_at(input: &str, offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut col = 1;
+    for ch in input[..offset.min(input.len())].chars() {
+        if ch == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_is_inclusive_of_start_and_exclusive_of_end() {
+        let spanned = Spanned::new("note", 4..8);
+        assert!(!spanned.contains(3));
+        assert!(spanned.contains(4));
+        assert!(spanned.contains(7));
+        assert!(!spanned.contains(8));
+    }
+
+    #[test]
+    fn deref_reaches_through_to_the_wrapped_node() {
+        let spanned = Spanned::new(vec![1, 2, 3], 0..1);
+        assert_eq!(spanned.len(), 3);
+    }
+
+    #[test]
+    fn line_col_at_counts_newlines_and_resets_the_column() {
+        let input = "cde\nfga\nb";
+        assert_eq!(line_col_at(input, 0), (1, 1));
+        assert_eq!(line_col_at(input, 3), (1, 4)); // pointing at the newline itself
+        assert_eq!(line_col_at(input, 4), (2, 1)); // first char of line 2
+        assert_eq!(line_col_at(input, 8), (3, 1)); // the final 'b'
+    }
+
+    #[test]
+    fn line_col_resolves_the_spans_start_not_its_end() {
+        let input = "cde\nfga";
+        let spanned = Spanned::new((), 4..7);
+        assert_eq!(spanned.line_col(input), line_col_at(input, 4));
+    }
+}